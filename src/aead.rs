@@ -0,0 +1,884 @@
+use crate::handshake::{self, EphemeralKeypair, StaticIdentity};
+use crate::rc4::generate_key;
+use aead::{Aead, NewAead};
+use aes_gcm::Aes256Gcm;
+use bytes::{BufMut, BytesMut};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha1::Sha1;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use x25519_dalek::PublicKey;
+
+const TAG_LEN: usize = 16;
+const LEN_BYTES: usize = 2;
+const MAX_CHUNK_LEN: usize = 0x3FFF;
+const NONCE_LEN: usize = 12;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+// the top bit of the (14-bit-significant) chunk length field can never be
+// set by a real payload length, so it doubles as a tag marking the chunk
+// as an in-band control message (used for rekeying) rather than data.
+const CONTROL_FLAG: u16 = 0x8000;
+const REKEY_PROPOSE: u8 = 1;
+const REKEY_ACK: u8 = 2;
+
+/// Shadowsocks AEAD methods supported alongside the legacy RC4-MD5 stream
+/// cipher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Chacha20Poly1305,
+    Aes256Gcm,
+}
+
+impl Method {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chacha20-poly1305" => Some(Method::Chacha20Poly1305),
+            "aes-256-gcm" => Some(Method::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    // both supported methods use a 32 byte key, which also doubles as the
+    // salt length, per the Shadowsocks AEAD construction.
+    pub fn key_len(&self) -> usize {
+        32
+    }
+
+    pub fn salt_len(&self) -> usize {
+        self.key_len()
+    }
+
+    // derives the master key from the password via the existing
+    // EVP_BytesToKey-style expansion used by RC4-MD5.
+    pub fn derive_master_key(&self, password: &[u8]) -> Vec<u8> {
+        generate_key(password, self.key_len())
+    }
+}
+
+enum Cipher {
+    Chacha20Poly1305(ChaCha20Poly1305),
+    // boxed since Aes256Gcm is ~30x the size of ChaCha20Poly1305, and an
+    // unboxed variant would size every `Session` (and therefore every
+    // `AeadReader`/`AeadWriter`) to the larger of the two regardless of
+    // which method is actually in use.
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+impl Cipher {
+    fn new(method: Method, subkey: &[u8]) -> Self {
+        match method {
+            Method::Chacha20Poly1305 => {
+                Cipher::Chacha20Poly1305(ChaCha20Poly1305::new(subkey.into()))
+            }
+            Method::Aes256Gcm => Cipher::Aes256Gcm(Box::new(Aes256Gcm::new(subkey.into()))),
+        }
+    }
+
+    fn encrypt(&self, nonce: &[u8; NONCE_LEN], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let result = match self {
+            Cipher::Chacha20Poly1305(c) => c.encrypt(nonce.into(), plaintext),
+            Cipher::Aes256Gcm(c) => c.encrypt(nonce.into(), plaintext),
+        };
+        result.map_err(|_| Error::new(ErrorKind::Other, "aead encrypt failed"))
+    }
+
+    fn decrypt(&self, nonce: &[u8; NONCE_LEN], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let result = match self {
+            Cipher::Chacha20Poly1305(c) => c.decrypt(nonce.into(), ciphertext),
+            Cipher::Aes256Gcm(c) => c.decrypt(nonce.into(), ciphertext),
+        };
+        result.map_err(|_| Error::new(ErrorKind::Other, "aead decrypt failed"))
+    }
+}
+
+// derives the per-session subkey via HKDF-SHA1(key=master_key, salt=salt,
+// info="ss-subkey"), as specified by the Shadowsocks AEAD construction.
+fn derive_subkey(master_key: &[u8], salt: &[u8], key_len: usize) -> Vec<u8> {
+    let hk = Hkdf::<Sha1>::new(Some(salt), master_key);
+    let mut subkey = vec![0u8; key_len];
+    hk.expand(SUBKEY_INFO, &mut subkey)
+        .expect("subkey length is always valid for HKDF-SHA1");
+    subkey
+}
+
+fn next_nonce(nonce: &mut [u8; NONCE_LEN]) {
+    for byte in nonce.iter_mut() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
+
+fn generate_salt(len: usize) -> Vec<u8> {
+    let mut salt = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkKind {
+    Data,
+    Control,
+}
+
+struct Session {
+    cipher: Cipher,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Session {
+    fn new(method: Method, master_key: &[u8], salt: &[u8]) -> Self {
+        let subkey = derive_subkey(master_key, salt, method.key_len());
+        Self {
+            cipher: Cipher::new(method, &subkey),
+            nonce: [0u8; NONCE_LEN],
+        }
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let sealed = self.cipher.encrypt(&self.nonce, plaintext)?;
+        next_nonce(&mut self.nonce);
+        Ok(sealed)
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let opened = self.cipher.decrypt(&self.nonce, ciphertext)?;
+        next_nonce(&mut self.nonce);
+        Ok(opened)
+    }
+}
+
+// Encrypts a single UDP datagram as `[salt][sealed payload][tag]`, using a
+// fresh salt (and therefore a fresh subkey) per packet since UDP datagrams
+// may arrive out of order or not at all.
+pub fn seal_packet(method: Method, master_key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let salt = generate_salt(method.salt_len());
+    let mut session = Session::new(method, master_key, &salt);
+    let sealed = session.seal(plaintext)?;
+    let mut packet = salt;
+    packet.extend_from_slice(&sealed);
+    Ok(packet)
+}
+
+// Reverses `seal_packet`: splits off the leading salt, derives the subkey
+// and opens the remainder.
+pub fn open_packet(method: Method, master_key: &[u8], packet: &[u8]) -> Result<Vec<u8>, Error> {
+    let salt_len = method.salt_len();
+    if packet.len() < salt_len {
+        return Err(Error::new(ErrorKind::Other, "udp packet shorter than salt"));
+    }
+    let (salt, sealed) = packet.split_at(salt_len);
+    let mut session = Session::new(method, master_key, salt);
+    session.open(sealed)
+}
+
+pub struct AeadWriter<S: AsyncWrite + Unpin> {
+    w: S,
+    method: Method,
+    master_key: Vec<u8>,
+    session: Option<Session>,
+    // a framed chunk (salt, if this is the first one, plus sealed length
+    // and payload) queued for `w` but not yet fully accepted by it; used by
+    // the `AsyncWrite` impl below, which can't simply `.await` like the
+    // inherent `write`/`write_control` methods do.
+    pending: BytesMut,
+    pending_written: usize,
+}
+
+impl<S: AsyncWrite + Unpin> AeadWriter<S> {
+    pub fn new(w: S, method: Method, master_key: Vec<u8>) -> Self {
+        Self {
+            w,
+            method,
+            master_key,
+            session: None,
+            pending: BytesMut::new(),
+            pending_written: 0,
+        }
+    }
+
+    async fn session(&mut self) -> Result<&mut Session, Error> {
+        if self.session.is_none() {
+            let salt = generate_salt(self.method.salt_len());
+            self.w.write_all(&salt).await?;
+            self.session = Some(Session::new(self.method, &self.master_key, &salt));
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    async fn write_framed(&mut self, kind: ChunkKind, payload: &[u8]) -> Result<(), Error> {
+        let mut len_field = payload.len() as u16;
+        if kind == ChunkKind::Control {
+            len_field |= CONTROL_FLAG;
+        }
+        let session = self.session().await?;
+        let sealed_len = session.seal(&len_field.to_be_bytes())?;
+        let sealed_payload = session.seal(payload)?;
+        self.w.write_all(&sealed_len).await?;
+        self.w.write_all(&sealed_payload).await?;
+        Ok(())
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        for chunk in buf.chunks(MAX_CHUNK_LEN) {
+            self.write_framed(ChunkKind::Data, chunk).await?;
+        }
+        Ok(())
+    }
+
+    // sends an in-band control chunk (currently only used for rekeying)
+    // under the writer's *current* session, same as regular data.
+    pub(crate) async fn write_control(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.write_framed(ChunkKind::Control, payload).await
+    }
+
+    // installs a freshly rekeyed session in place of the current one; the
+    // old session is simply dropped since the peer's reader tolerates the
+    // in-flight window by keeping its own previous session as a fallback.
+    pub(crate) fn install_new_session(&mut self, master_key: Vec<u8>, salt: &[u8]) {
+        self.session = Some(Session::new(self.method, &master_key, salt));
+        self.master_key = master_key;
+    }
+
+    // drains ciphertext queued by a previous `poll_write` that `w` hasn't
+    // accepted yet.
+    fn poll_flush_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while self.pending_written < self.pending.len() {
+            match Pin::new(&mut self.w).poll_write(cx, &self.pending[self.pending_written..]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )))
+                }
+                Poll::Ready(Ok(n)) => self.pending_written += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.pending.clear();
+        self.pending_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Plain data-only path for `AeadWriter`, used wherever a connection isn't
+// also juggling in-band control chunks (rekeying): seals at most one
+// `MAX_CHUNK_LEN` chunk of `buf` per call, the same per-call granularity as
+// `write`'s `buf.chunks(MAX_CHUNK_LEN)` loop, so callers like
+// `tokio::io::copy_bidirectional` can drive it without a hand-rolled loop.
+// The handshake/rekey path still uses the inherent `write`/`write_control`
+// async methods above, since it also needs to interleave control chunks.
+impl<S: AsyncWrite + Unpin> AsyncWrite for AeadWriter<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+
+        if this.pending_written < this.pending.len() {
+            match this.poll_flush_pending(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let chunk = &buf[..std::cmp::min(buf.len(), MAX_CHUNK_LEN)];
+
+        let mut framed = BytesMut::new();
+        if this.session.is_none() {
+            let salt = generate_salt(this.method.salt_len());
+            this.session = Some(Session::new(this.method, &this.master_key, &salt));
+            framed.put_slice(&salt);
+        }
+        let session = this.session.as_mut().unwrap();
+
+        let sealed_len = match session.seal(&(chunk.len() as u16).to_be_bytes()) {
+            Ok(s) => s,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        let sealed_payload = match session.seal(chunk) {
+            Ok(s) => s,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        framed.put_slice(&sealed_len);
+        framed.put_slice(&sealed_payload);
+
+        this.pending = framed;
+        this.pending_written = 0;
+        // best-effort: push the freshly sealed chunk out immediately; any
+        // bytes that don't fit stay in `pending` for the next call, since
+        // the plaintext is already accepted at this point.
+        let _ = this.poll_flush_pending(cx);
+
+        Poll::Ready(Ok(chunk.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        match this.poll_flush_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.w).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        match this.poll_flush_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.w).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+pub struct AeadReader<S: AsyncRead + Unpin> {
+    r: S,
+    method: Method,
+    master_key: Vec<u8>,
+    session: Option<Session>,
+    // kept alive after a rekey so chunks the peer sent just before
+    // switching to the new key can still be decrypted.
+    previous_session: Option<Session>,
+    pending: BytesMut,
+    pending_control: VecDeque<Vec<u8>>,
+    // bytes of the salt/length-header/payload currently being accumulated
+    // by the `AsyncRead` impl below across possibly-partial inner reads;
+    // unused by the inherent `read`/`read_chunk` async methods, which just
+    // `.await` a `read_exact` instead.
+    salt_progress: Vec<u8>,
+    len_progress: Vec<u8>,
+    payload_progress: Option<(ChunkKind, usize, Vec<u8>)>,
+}
+
+impl<S: AsyncRead + Unpin> AeadReader<S> {
+    pub fn new(r: S, method: Method, master_key: Vec<u8>) -> Self {
+        Self {
+            r,
+            method,
+            master_key,
+            session: None,
+            previous_session: None,
+            pending: BytesMut::new(),
+            pending_control: VecDeque::new(),
+            salt_progress: Vec::new(),
+            len_progress: Vec::new(),
+            payload_progress: None,
+        }
+    }
+
+    async fn ensure_session(&mut self) -> Result<(), Error> {
+        if self.session.is_none() {
+            let mut salt = vec![0u8; self.method.salt_len()];
+            self.r.read_exact(&mut salt).await?;
+            self.session = Some(Session::new(self.method, &self.master_key, &salt));
+        }
+        Ok(())
+    }
+
+    // opens `ciphertext` with the current session, falling back to the
+    // previous one (if any) to tolerate chunks still in flight from the
+    // writer's pre-rekey key.
+    fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        if let Some(session) = self.session.as_mut() {
+            if let Ok(plain) = session.open(ciphertext) {
+                return Ok(plain);
+            }
+        }
+        if let Some(session) = self.previous_session.as_mut() {
+            return session.open(ciphertext);
+        }
+        Err(Error::new(ErrorKind::Other, "aead decrypt failed"))
+    }
+
+    // installs a freshly rekeyed session, keeping the outgoing one as a
+    // fallback for chunks already in flight under the old key.
+    pub(crate) fn install_new_session_with_fallback(&mut self, master_key: Vec<u8>, salt: &[u8]) {
+        let new_session = Session::new(self.method, &master_key, salt);
+        self.previous_session = self.session.replace(new_session);
+        self.master_key = master_key;
+    }
+
+    pub(crate) fn take_control(&mut self) -> Option<Vec<u8>> {
+        self.pending_control.pop_front()
+    }
+
+    async fn read_chunk(&mut self) -> Result<(), Error> {
+        // make sure the salt has been read and the session established
+        // before we try to read any framed chunks.
+        self.ensure_session().await?;
+
+        let mut len_buf = vec![0u8; LEN_BYTES + TAG_LEN];
+        self.r.read_exact(&mut len_buf).await?;
+        let len_plain = self.open(&len_buf)?;
+        let len_field = u16::from_be_bytes([len_plain[0], len_plain[1]]);
+        let kind = if len_field & CONTROL_FLAG != 0 {
+            ChunkKind::Control
+        } else {
+            ChunkKind::Data
+        };
+        let len = (len_field & !CONTROL_FLAG) as usize & MAX_CHUNK_LEN;
+
+        let mut payload_buf = vec![0u8; len + TAG_LEN];
+        self.r.read_exact(&mut payload_buf).await?;
+        let payload = self.open(&payload_buf)?;
+
+        match kind {
+            ChunkKind::Data => self.pending.extend_from_slice(&payload),
+            ChunkKind::Control => self.pending_control.push_back(payload),
+        }
+        Ok(())
+    }
+
+    // reads decrypted data into `buf`, transparently skipping over any
+    // control chunks (stashed for `take_control` to pick up) until data is
+    // available.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.pending.is_empty() {
+            self.read_chunk().await?;
+        }
+        let n = std::cmp::min(buf.len(), self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        let _ = self.pending.split_to(n);
+        Ok(n)
+    }
+}
+
+// reads into `target` until it holds at least `want` bytes (appending to
+// whatever was already accumulated there by a previous, possibly-pending
+// call), or reports `Ok(true)` if the peer closed before that.
+fn poll_fill<R: AsyncRead + Unpin>(
+    r: &mut R,
+    cx: &mut Context<'_>,
+    target: &mut Vec<u8>,
+    want: usize,
+) -> Poll<Result<bool, Error>> {
+    while target.len() < want {
+        let mut tmp = vec![0u8; want - target.len()];
+        let mut tmp_buf = ReadBuf::new(&mut tmp);
+        match Pin::new(&mut *r).poll_read(cx, &mut tmp_buf) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        if tmp_buf.filled().is_empty() {
+            return Poll::Ready(Ok(true));
+        }
+        target.extend_from_slice(tmp_buf.filled());
+    }
+    Poll::Ready(Ok(false))
+}
+
+// Plain data-only path for `AeadReader`, the `AsyncRead` counterpart to
+// `AeadWriter`'s `AsyncWrite` impl above: transparently skips over control
+// chunks the same way the inherent `read` does, just driven by polls
+// instead of `.await` so it can feed `tokio::io::copy_bidirectional`. The
+// handshake/rekey path keeps using the inherent `read`/`take_control`
+// methods, since it needs to observe control chunks rather than skip them.
+impl<S: AsyncRead + Unpin> AsyncRead for AeadReader<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+
+        loop {
+            // serve anything already decrypted before reading more.
+            if !this.pending.is_empty() {
+                let n = std::cmp::min(buf.remaining(), this.pending.len());
+                buf.put_slice(&this.pending[..n]);
+                let _ = this.pending.split_to(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            // make sure the salt has been read and the session established
+            // before decrypting any chunks.
+            if this.session.is_none() {
+                let want = this.method.salt_len();
+                match poll_fill(&mut this.r, cx, &mut this.salt_progress, want) {
+                    Poll::Ready(Ok(eof)) => {
+                        if eof {
+                            // peer closed before sending a full salt
+                            return Poll::Ready(Ok(()));
+                        }
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+                this.session = Some(Session::new(this.method, &this.master_key, &this.salt_progress));
+                this.salt_progress.clear();
+            }
+
+            // read the length header (if we haven't already staged a
+            // payload from a previous one) before the payload it describes.
+            if this.payload_progress.is_none() {
+                let want = LEN_BYTES + TAG_LEN;
+                match poll_fill(&mut this.r, cx, &mut this.len_progress, want) {
+                    Poll::Ready(Ok(eof)) => {
+                        if eof {
+                            // peer closed between chunks, a clean EOF
+                            return Poll::Ready(Ok(()));
+                        }
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+                let len_progress = std::mem::take(&mut this.len_progress);
+                let len_plain = match this.open(&len_progress) {
+                    Ok(p) => p,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                let len_field = u16::from_be_bytes([len_plain[0], len_plain[1]]);
+                let kind = if len_field & CONTROL_FLAG != 0 {
+                    ChunkKind::Control
+                } else {
+                    ChunkKind::Data
+                };
+                let len = (len_field & !CONTROL_FLAG) as usize & MAX_CHUNK_LEN;
+                this.payload_progress = Some((kind, len + TAG_LEN, Vec::new()));
+            }
+
+            let (kind, want, _) = this.payload_progress.as_ref().unwrap();
+            let (kind, want) = (*kind, *want);
+            match poll_fill(&mut this.r, cx, &mut this.payload_progress.as_mut().unwrap().2, want) {
+                Poll::Ready(Ok(eof)) => {
+                    if eof {
+                        return Poll::Ready(Err(Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "peer closed mid-chunk",
+                        )));
+                    }
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let (_, _, payload_bytes) = this.payload_progress.take().unwrap();
+            let payload = match this.open(&payload_bytes) {
+                Ok(p) => p,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            match kind {
+                ChunkKind::Data => this.pending.extend_from_slice(&payload),
+                ChunkKind::Control => this.pending_control.push_back(payload),
+            }
+            // loop back: a `Data` chunk is now servable from `pending`; a
+            // `Control` chunk leaves `pending` empty, so we transparently
+            // read the next chunk instead of returning an empty read.
+        }
+    }
+}
+
+/// Configures automatic in-band rekeying for [`relay_with_handshake`].
+pub struct RekeyCfg {
+    pub threshold_bytes: u64,
+}
+
+/// The pieces of a [`relay_with_handshake`] session that stay fixed for the
+/// life of the connection, grouped so the function doesn't need one
+/// parameter per field.
+pub struct HandshakeSession {
+    pub method: Method,
+    pub identity: StaticIdentity,
+    pub peer_static: PublicKey,
+    pub rekey: RekeyCfg,
+}
+
+/// Relays a single SOCKS5 client <-> upstream Shadowsocks connection over
+/// an already-established handshake session (`ru`/`wu`), automatically
+/// rotating the session key in-band once `session.rekey.threshold_bytes`
+/// bytes have been written upstream, without tearing down the TCP
+/// connection. Each side's reader keeps its outgoing session alive as a
+/// fallback so that messages already in flight under the previous key
+/// still decrypt correctly.
+pub async fn relay_with_handshake<CR, CW, UR, UW>(
+    mut client_r: CR,
+    mut client_w: CW,
+    mut ru: AeadReader<UR>,
+    mut wu: AeadWriter<UW>,
+    session: HandshakeSession,
+) -> Result<(), Error>
+where
+    CR: AsyncRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    UR: AsyncRead + Unpin,
+    UW: AsyncWrite + Unpin,
+{
+    let HandshakeSession {
+        method,
+        identity,
+        peer_static,
+        rekey,
+    } = session;
+
+    let mut bytes_since_rekey: u64 = 0;
+    // our ephemeral key and the salt we proposed, kept until the peer acks.
+    let mut pending_propose: Option<(Vec<u8>, EphemeralKeypair)> = None;
+
+    let mut client_buf = [0u8; 4096];
+    let mut upstream_buf = [0u8; 4096];
+
+    loop {
+        tokio::select! {
+            res = client_r.read(&mut client_buf) => {
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                wu.write(&client_buf[..n]).await?;
+
+                bytes_since_rekey += n as u64;
+                if pending_propose.is_none() && bytes_since_rekey >= rekey.threshold_bytes {
+                    let salt = generate_salt(method.salt_len());
+                    let ephemeral = EphemeralKeypair::generate();
+                    let mut propose = vec![REKEY_PROPOSE];
+                    propose.extend_from_slice(&salt);
+                    propose.extend_from_slice(ephemeral.public.as_bytes());
+                    wu.write_control(&propose).await?;
+                    pending_propose = Some((salt, ephemeral));
+                    bytes_since_rekey = 0;
+                }
+            }
+            res = ru.read(&mut upstream_buf) => {
+                let n = res?;
+                if n == 0 {
+                    break;
+                }
+                client_w.write_all(&upstream_buf[..n]).await?;
+
+                while let Some(ctrl) = ru.take_control() {
+                    handle_rekey_control(
+                        &ctrl,
+                        &identity,
+                        &peer_static,
+                        &mut pending_propose,
+                        &mut ru,
+                        &mut wu,
+                    )
+                    .await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle_rekey_control<UR, UW>(
+    ctrl: &[u8],
+    identity: &StaticIdentity,
+    peer_static: &PublicKey,
+    pending_propose: &mut Option<(Vec<u8>, EphemeralKeypair)>,
+    ru: &mut AeadReader<UR>,
+    wu: &mut AeadWriter<UW>,
+) -> Result<(), Error>
+where
+    UR: AsyncRead + Unpin,
+    UW: AsyncWrite + Unpin,
+{
+    let key_len = 32;
+    match ctrl.first() {
+        Some(&REKEY_PROPOSE) if ctrl.len() == 1 + key_len + key_len => {
+            let salt = ctrl[1..1 + key_len].to_vec();
+            let mut peer_ephemeral_bytes = [0u8; 32];
+            peer_ephemeral_bytes.copy_from_slice(&ctrl[1 + key_len..]);
+            let peer_ephemeral = PublicKey::from(peer_ephemeral_bytes);
+
+            if let Some((_, our_ephemeral)) = pending_propose.as_ref() {
+                // Both sides crossed the rekey threshold around the same
+                // time and each proposed independently. Static keys can't
+                // break this tie: in the common `TrustConfig::SharedSecret`
+                // deployment both ends derive the *same* static identity
+                // from the shared password, so `identity.public_key() ==
+                // peer_static` and a static-key comparison can't pick a
+                // winner. Compare the fresh, independently-random ephemeral
+                // keys embedded in each side's own proposal instead, so both
+                // peers make the same decision regardless of which
+                // REKEY_PROPOSE arrived first: whoever's ephemeral key is
+                // lexicographically smaller keeps its own outstanding
+                // proposal (to be resolved by the REKEY_ACK below), the
+                // other drops its proposal and adopts the peer's instead.
+                // Without this, both sides could each install a different
+                // derived key and desync.
+                if our_ephemeral.public.as_bytes().as_slice() < peer_ephemeral_bytes.as_slice() {
+                    println!("ignoring concurrent rekey propose; our proposal wins the tie-break");
+                    return Ok(());
+                }
+                *pending_propose = None;
+            }
+
+            let ephemeral = EphemeralKeypair::generate();
+            let our_ephemeral_pub = *ephemeral.public.as_bytes();
+            let dh_ephemeral = ephemeral.diffie_hellman(&peer_ephemeral);
+            let dh_static = identity.diffie_hellman(peer_static);
+            let new_key = handshake::derive_session_key(&dh_ephemeral, &dh_static);
+
+            let mut ack = vec![REKEY_ACK];
+            ack.extend_from_slice(&our_ephemeral_pub);
+            wu.write_control(&ack).await?;
+
+            wu.install_new_session(new_key.clone(), &salt);
+            ru.install_new_session_with_fallback(new_key, &salt);
+        }
+        Some(&REKEY_ACK) if ctrl.len() == 1 + key_len => {
+            if let Some((salt, ephemeral)) = pending_propose.take() {
+                let mut peer_ephemeral_bytes = [0u8; 32];
+                peer_ephemeral_bytes.copy_from_slice(&ctrl[1..]);
+                let peer_ephemeral = PublicKey::from(peer_ephemeral_bytes);
+
+                let dh_ephemeral = ephemeral.diffie_hellman(&peer_ephemeral);
+                let dh_static = identity.diffie_hellman(peer_static);
+                let new_key = handshake::derive_session_key(&dh_ephemeral, &dh_static);
+
+                wu.install_new_session(new_key.clone(), &salt);
+                ru.install_new_session_with_fallback(new_key, &salt);
+            }
+        }
+        _ => {
+            println!("ignoring malformed rekey control message");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_open_roundtrip_chacha20poly1305() {
+        let master_key = vec![9u8; 32];
+        let plaintext = b"hello shadowsocks aead";
+        let packet = seal_packet(Method::Chacha20Poly1305, &master_key, plaintext).unwrap();
+        let opened = open_packet(Method::Chacha20Poly1305, &master_key, &packet).unwrap();
+        assert_eq!(plaintext.to_vec(), opened);
+    }
+
+    #[test]
+    fn seal_open_roundtrip_aes256gcm() {
+        let master_key = vec![3u8; 32];
+        let plaintext = b"hello shadowsocks aead over aes-gcm";
+        let packet = seal_packet(Method::Aes256Gcm, &master_key, plaintext).unwrap();
+        let opened = open_packet(Method::Aes256Gcm, &master_key, &packet).unwrap();
+        assert_eq!(plaintext.to_vec(), opened);
+    }
+
+    #[tokio::test]
+    async fn concurrent_rekey_propose_tie_breaks_deterministically() {
+        let method = Method::Chacha20Poly1305;
+        let master_key = vec![5u8; 32];
+
+        let identity_a = StaticIdentity::from_password(b"node-a-password");
+        let identity_b = StaticIdentity::from_password(b"node-b-password");
+
+        let mut ru_a = AeadReader::new(tokio::io::empty(), method, master_key.clone());
+        let mut wu_a = AeadWriter::new(tokio::io::sink(), method, master_key.clone());
+        let our_ephemeral = EphemeralKeypair::generate();
+        let our_ephemeral_pub = *our_ephemeral.public.as_bytes();
+        let mut pending_a: Option<(Vec<u8>, EphemeralKeypair)> =
+            Some((vec![0u8; 32], our_ephemeral));
+
+        // a concurrent REKEY_PROPOSE arriving from b, while a has its own
+        // proposal outstanding.
+        let peer_ephemeral_pub = *EphemeralKeypair::generate().public.as_bytes();
+        let mut propose_from_b = vec![REKEY_PROPOSE];
+        propose_from_b.extend_from_slice(&[1u8; 32]);
+        propose_from_b.extend_from_slice(&peer_ephemeral_pub);
+
+        let a_wins = our_ephemeral_pub.as_slice() < peer_ephemeral_pub.as_slice();
+
+        handle_rekey_control(
+            &propose_from_b,
+            &identity_a,
+            &identity_b.public_key(),
+            &mut pending_a,
+            &mut ru_a,
+            &mut wu_a,
+        )
+        .await
+        .unwrap();
+
+        if a_wins {
+            assert!(
+                pending_a.is_some(),
+                "the side with the smaller ephemeral key should keep its own pending proposal"
+            );
+        } else {
+            assert!(
+                pending_a.is_none(),
+                "the side with the larger ephemeral key should drop its proposal and adopt the peer's"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_rekey_propose_tie_breaks_even_with_identical_static_keys() {
+        // `TrustConfig::SharedSecret` derives the *same* static identity on
+        // both ends from the shared password, so a tie-break that compares
+        // static keys can never pick a winner here; it must fall back to
+        // the (always-distinct) ephemeral keys instead.
+        let method = Method::Chacha20Poly1305;
+        let master_key = vec![5u8; 32];
+        let identity = StaticIdentity::from_password(b"shared-rekey-password");
+        let peer_static = identity.public_key();
+
+        let mut ru = AeadReader::new(tokio::io::empty(), method, master_key.clone());
+        let mut wu = AeadWriter::new(tokio::io::sink(), method, master_key.clone());
+        let our_ephemeral = EphemeralKeypair::generate();
+        let our_ephemeral_pub = *our_ephemeral.public.as_bytes();
+        let mut pending: Option<(Vec<u8>, EphemeralKeypair)> = Some((vec![0u8; 32], our_ephemeral));
+
+        let peer_ephemeral_pub = *EphemeralKeypair::generate().public.as_bytes();
+        let mut propose = vec![REKEY_PROPOSE];
+        propose.extend_from_slice(&[2u8; 32]);
+        propose.extend_from_slice(&peer_ephemeral_pub);
+
+        let we_win = our_ephemeral_pub.as_slice() < peer_ephemeral_pub.as_slice();
+
+        handle_rekey_control(&propose, &identity, &peer_static, &mut pending, &mut ru, &mut wu)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            pending.is_some(),
+            we_win,
+            "tie-break must resolve deterministically via ephemeral keys even when static keys are identical"
+        );
+    }
+
+    // A 1-byte duplex buffer forces every inner `poll_read`/`poll_write` to
+    // see only a single byte at a time, so a round trip through it only
+    // succeeds if `AeadReader`'s `salt_progress`/`len_progress`/
+    // `payload_progress` and `AeadWriter`'s `pending`/`pending_written`
+    // correctly accumulate across many partial poll calls instead of
+    // assuming one call sees a whole salt, length header, or payload.
+    #[tokio::test]
+    async fn aead_reader_writer_round_trip_one_byte_at_a_time() {
+        let (client, server) = tokio::io::duplex(1);
+        let method = Method::Chacha20Poly1305;
+        let master_key = vec![7u8; 32];
+
+        let mut writer = AeadWriter::new(client, method, master_key.clone());
+        let mut reader = AeadReader::new(server, method, master_key);
+
+        let plaintext = b"the quick brown fox jumps over the lazy dog";
+
+        let write_task = tokio::spawn(async move {
+            writer.write_all(plaintext).await.unwrap();
+            writer.flush().await.unwrap();
+        });
+
+        let mut got = vec![0u8; plaintext.len()];
+        reader.read_exact(&mut got).await.unwrap();
+
+        write_task.await.unwrap();
+        assert_eq!(got, plaintext);
+    }
+}
@@ -0,0 +1,64 @@
+use std::io::Error;
+
+/// A pluggable Shadowsocks cipher: knows the key and salt/IV sizes it
+/// needs, and starts a fresh [`CipherSession`] once a salt (or IV) has been
+/// exchanged with the peer.
+pub trait Cipher: Send + Sync {
+    fn key_size(&self) -> usize;
+    fn salt_or_iv_size(&self) -> usize;
+    fn new_session(&self, key: &[u8], salt_or_iv: &[u8]) -> Box<dyn CipherSession>;
+
+    // derives the master key from the password via the EVP_BytesToKey-style
+    // expansion shared by all methods, sized to this cipher's `key_size()`.
+    fn derive_master_key(&self, password: &[u8]) -> Vec<u8> {
+        crate::rc4::generate_key(password, self.key_size())
+    }
+}
+
+/// A single en/decryption session, keyed for one connection.
+///
+/// Every cipher registered under [`CipherKind`] is, like `Rc4`, a plain
+/// stream cipher: `encrypt_chunk`/`decrypt_chunk` return a buffer the same
+/// length as their input (`overhead() == 0`), so
+/// [`crate::stream::Rc4Reader`]/[`crate::stream::Rc4Writer`] can treat a
+/// session as a transparent in-place transform with no wire framing of its
+/// own. `overhead()` exists so that contract is checkable rather than just
+/// assumed; it's intentionally not exercised by any cipher yet. The AEAD
+/// methods are a different shape entirely (they append an authentication
+/// tag to every chunk and need length-prefixed chunk framing), which is why
+/// they're *not* registered here — [`crate::aead::AeadReader`]/
+/// [`crate::aead::AeadWriter`] implement that framing directly instead of
+/// going through this trait.
+pub trait CipherSession: Send {
+    fn encrypt_chunk(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Error>;
+    fn decrypt_chunk(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error>;
+    fn overhead(&self) -> usize;
+}
+
+/// Maps a Shadowsocks *stream-cipher* method name to its `Cipher`
+/// implementation. AEAD methods (`chacha20-poly1305`, `aes-256-gcm`) are
+/// deliberately not registered here: `CipherKind::build()` is only ever fed
+/// into [`crate::stream::Rc4Reader`]/[`crate::stream::Rc4Writer`], which
+/// don't implement AEAD's length-prefix + per-chunk-tag framing, so an AEAD
+/// cipher plugged in through this registry would silently produce
+/// undecryptable ciphertext. `main.rs` selects between this registry and
+/// [`crate::aead::Method`] up front, before either reader/writer pair is
+/// constructed.
+pub enum CipherKind {
+    Rc4Md5,
+}
+
+impl CipherKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rc4-md5" => Some(CipherKind::Rc4Md5),
+            _ => None,
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn Cipher> {
+        match self {
+            CipherKind::Rc4Md5 => Box::new(crate::rc4::Rc4Cipher),
+        }
+    }
+}
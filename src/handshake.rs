@@ -0,0 +1,220 @@
+use crate::rc4::generate_key;
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+const SESSION_KEY_INFO: &[u8] = b"ss-handshake-session-key";
+pub const SESSION_KEY_LEN: usize = 32;
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// A node's long-lived x25519 identity, used to authenticate the ephemeral
+/// handshake so a leaked password (or a stolen ephemeral key) alone can't
+/// retroactively decrypt captured traffic.
+pub struct StaticIdentity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl StaticIdentity {
+    /// Derives a deterministic identity keypair from the shared password,
+    /// so both ends of a shared-secret deployment trust the same derived
+    /// public key without exchanging anything out of band.
+    pub fn from_password(password: &[u8]) -> Self {
+        let seed = generate_key(password, PUBLIC_KEY_LEN);
+        let mut seed_bytes = [0u8; PUBLIC_KEY_LEN];
+        seed_bytes.copy_from_slice(&seed[..PUBLIC_KEY_LEN]);
+        let secret = StaticSecret::from(seed_bytes);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// Generates a fresh random identity keypair, for explicit-trust
+    /// deployments. Each call yields a new keypair; nothing here saves or
+    /// reloads it, so its public key does not survive a restart.
+    pub fn generate() -> Self {
+        let secret = StaticSecret::new(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    pub fn diffie_hellman(&self, peer: &PublicKey) -> [u8; 32] {
+        self.secret.diffie_hellman(peer).to_bytes()
+    }
+}
+
+/// A fresh keypair generated for a single handshake (or rekey), discarded
+/// once the shared secret has been derived, so that key is never recovered
+/// from the peers' long-term state.
+pub struct EphemeralKeypair {
+    secret: EphemeralSecret,
+    pub public: PublicKey,
+}
+
+impl EphemeralKeypair {
+    pub fn generate() -> Self {
+        let secret = EphemeralSecret::new(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn diffie_hellman(self, peer: &PublicKey) -> [u8; 32] {
+        self.secret.diffie_hellman(peer).to_bytes()
+    }
+}
+
+/// Which peer identities a node is willing to complete a handshake with.
+pub enum TrustConfig {
+    /// Trust whichever peer derives the same static key from the shared
+    /// password (i.e. anyone who knows the password).
+    SharedSecret,
+    /// Trust only peers whose static public key appears in this set,
+    /// loaded from a local file.
+    Explicit(HashSet<[u8; 32]>),
+}
+
+impl TrustConfig {
+    /// Loads a newline-separated list of hex-encoded x25519 public keys.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn load_trusted_peers(path: &str) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)?;
+        let mut peers = HashSet::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bytes = hex_decode(line)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bad hex public key"))?;
+            if bytes.len() != PUBLIC_KEY_LEN {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "public key must be 32 bytes",
+                ));
+            }
+            let mut key = [0u8; PUBLIC_KEY_LEN];
+            key.copy_from_slice(&bytes);
+            peers.insert(key);
+        }
+        Ok(TrustConfig::Explicit(peers))
+    }
+
+    fn is_trusted(&self, identity: &StaticIdentity, peer_static: &PublicKey) -> bool {
+        match self {
+            TrustConfig::SharedSecret => peer_static.as_bytes() == identity.public.as_bytes(),
+            TrustConfig::Explicit(peers) => peers.contains(peer_static.as_bytes()),
+        }
+    }
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Derives the session encryption key from the handshake's DH outputs:
+/// ephemeral-ephemeral (for forward secrecy) combined with static-static
+/// (to authenticate the peer), via HKDF-SHA256.
+pub fn derive_session_key(dh_ephemeral: &[u8], dh_static: &[u8]) -> Vec<u8> {
+    let mut ikm = Vec::with_capacity(dh_ephemeral.len() + dh_static.len());
+    ikm.extend_from_slice(dh_ephemeral);
+    ikm.extend_from_slice(dh_static);
+    let hk = Hkdf::<Sha256>::new(None, &ikm);
+    let mut key = vec![0u8; SESSION_KEY_LEN];
+    hk.expand(SESSION_KEY_INFO, &mut key)
+        .expect("session key length is always valid for HKDF-SHA256");
+    key
+}
+
+/// Result of a completed handshake: the derived session key plus the
+/// peer's verified static public key, which is needed again if the session
+/// later rekeys.
+pub struct HandshakeResult {
+    pub session_key: Vec<u8>,
+    pub peer_static: PublicKey,
+}
+
+/// Performs the authenticated x25519 handshake over `stream`, before any
+/// application data is sent: both sides exchange their static identity key
+/// and a fresh ephemeral key, verify the peer's static key against `trust`,
+/// and derive a shared session key from ephemeral-ephemeral DH (forward
+/// secrecy) combined with static-static DH (authentication).
+pub async fn perform<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    identity: &StaticIdentity,
+    trust: &TrustConfig,
+) -> Result<HandshakeResult, Error> {
+    let ephemeral = EphemeralKeypair::generate();
+
+    let mut outgoing = Vec::with_capacity(2 * PUBLIC_KEY_LEN);
+    outgoing.extend_from_slice(identity.public.as_bytes());
+    outgoing.extend_from_slice(ephemeral.public.as_bytes());
+    stream.write_all(&outgoing).await?;
+
+    let mut incoming = [0u8; 2 * PUBLIC_KEY_LEN];
+    stream.read_exact(&mut incoming).await?;
+    let mut peer_static_bytes = [0u8; PUBLIC_KEY_LEN];
+    peer_static_bytes.copy_from_slice(&incoming[..PUBLIC_KEY_LEN]);
+    let mut peer_ephemeral_bytes = [0u8; PUBLIC_KEY_LEN];
+    peer_ephemeral_bytes.copy_from_slice(&incoming[PUBLIC_KEY_LEN..]);
+    let peer_static = PublicKey::from(peer_static_bytes);
+    let peer_ephemeral = PublicKey::from(peer_ephemeral_bytes);
+
+    if !trust.is_trusted(identity, &peer_static) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "peer static key is not trusted",
+        ));
+    }
+
+    let dh_ephemeral = ephemeral.diffie_hellman(&peer_ephemeral);
+    let dh_static = identity.diffie_hellman(&peer_static);
+
+    Ok(HandshakeResult {
+        session_key: derive_session_key(&dh_ephemeral, &dh_static),
+        peer_static,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_round_trip_derives_matching_session_key() {
+        let (mut a_stream, mut b_stream) = tokio::io::duplex(4096);
+
+        let identity_a = StaticIdentity::from_password(b"shared-handshake-password");
+        let identity_b = StaticIdentity::from_password(b"shared-handshake-password");
+
+        let (result_a, result_b) = tokio::join!(
+            perform(&mut a_stream, &identity_a, &TrustConfig::SharedSecret),
+            perform(&mut b_stream, &identity_b, &TrustConfig::SharedSecret),
+        );
+        let result_a = result_a.unwrap();
+        let result_b = result_b.unwrap();
+
+        assert_eq!(result_a.session_key, result_b.session_key);
+        assert_eq!(
+            result_a.peer_static.as_bytes(),
+            identity_b.public_key().as_bytes()
+        );
+        assert_eq!(
+            result_b.peer_static.as_bytes(),
+            identity_a.public_key().as_bytes()
+        );
+    }
+}
@@ -0,0 +1,5 @@
+pub mod aead;
+pub mod cipher;
+pub mod handshake;
+pub mod rc4;
+pub mod stream;
@@ -1,13 +1,17 @@
-use ss::rc4::Rc4;
-use ss::stream::{Rc4Reader, Rc4Writer};
+use ss::aead::{self, Method as AeadMethod};
+use ss::aead::{AeadReader, AeadWriter, HandshakeSession, RekeyCfg};
+use ss::cipher::CipherKind;
+use ss::handshake::{self, StaticIdentity, TrustConfig};
+use ss::stream::{self, Rc4Reader, Rc4Writer};
 use std::io::{Cursor, Error, ErrorKind};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use structopt::StructOpt;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
 const SOCKS5_VER: u8 = 5;
 const CMD_BIND: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
 const ATYP_IPV4: u8 = 1;
 const ATYPE_HOST: u8 = 3;
 const ATYPE_IPV6: u8 = 4;
@@ -26,6 +30,28 @@ struct Cfg {
     /// ss server password
     #[structopt(short = "p")]
     password: String,
+
+    /// encryption method, one of: rc4-md5, chacha20-poly1305, aes-256-gcm
+    #[structopt(short = "m", long = "method", default_value = "rc4-md5")]
+    method: String,
+
+    /// perform an x25519 authenticated handshake with forward secrecy and
+    /// automatic rekeying before relaying data (requires an AEAD method)
+    #[structopt(long = "handshake")]
+    handshake: bool,
+
+    /// file of hex-encoded trusted peer x25519 public keys; when set, the
+    /// handshake only accepts peers in this set instead of trusting
+    /// whichever peer derives the same password-based static key; this
+    /// node's own identity is then a fresh random keypair generated for
+    /// each connection, not loaded from a file
+    #[structopt(long = "trusted-peers")]
+    trusted_peers: Option<String>,
+
+    /// number of bytes written upstream before the handshake session
+    /// triggers an in-band rekey
+    #[structopt(long = "rekey-after-bytes", default_value = "1073741824")]
+    rekey_after_bytes: u64,
 }
 
 async fn handle(mut stream: TcpStream, cfg: Cfg) -> Result<(), Error> {
@@ -69,14 +95,131 @@ async fn handle(mut stream: TcpStream, cfg: Cfg) -> Result<(), Error> {
     if ver != SOCKS5_VER {
         return Err(Error::new(ErrorKind::Other, "not supported ver"));
     }
+
+    println!("atype {}", atype);
+    let (mut raw_addr, addr) = read_dst_addr(&mut stream, atype).await?;
+
+    if cmd == CMD_UDP_ASSOCIATE {
+        return handle_udp_associate(stream, cfg).await;
+    }
     if cmd != CMD_BIND {
         return Err(Error::new(ErrorKind::Other, "not supported cmd"));
     }
 
-    println!("atype {}", atype);
+    stream
+        .write(&[SOCKS5_VER, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await?;
+
+    println!("start proxying");
+    println!(
+        "raw addr {:?}, proxy addr: {} by {}",
+        raw_addr, addr?, cfg.server_addr
+    );
+
+    // proxy addr
+    let mut upstream = TcpStream::connect(cfg.server_addr.as_str()).await?;
+
+    if cfg.handshake {
+        let method = AeadMethod::from_name(&cfg.method)
+            .ok_or_else(|| Error::new(ErrorKind::Other, "--handshake requires an AEAD method"))?;
+
+        let identity = if cfg.trusted_peers.is_some() {
+            StaticIdentity::generate()
+        } else {
+            StaticIdentity::from_password(cfg.password.as_bytes())
+        };
+        let trust = match &cfg.trusted_peers {
+            Some(path) => TrustConfig::load_trusted_peers(path)?,
+            None => TrustConfig::SharedSecret,
+        };
+        let result = handshake::perform(&mut upstream, &identity, &trust).await?;
+
+        let (rl, wl) = stream.into_split();
+        let (ru, wu) = upstream.into_split();
+        let ru = AeadReader::new(ru, method, result.session_key.clone());
+        let mut wu = AeadWriter::new(wu, method, result.session_key);
+
+        // write addr first
+        wu.write(raw_addr.as_mut_slice()).await?;
+
+        return aead::relay_with_handshake(
+            rl,
+            wl,
+            ru,
+            wu,
+            HandshakeSession {
+                method,
+                identity,
+                peer_static: result.peer_static,
+                rekey: RekeyCfg {
+                    threshold_bytes: cfg.rekey_after_bytes,
+                },
+            },
+        )
+        .await;
+    }
+
+    let (rl, wl) = stream.into_split();
+    let (ru, wu) = upstream.into_split();
+
+    if let Some(method) = AeadMethod::from_name(&cfg.method) {
+        let master_key = method.derive_master_key(cfg.password.as_bytes());
+
+        let ru = AeadReader::new(ru, method, master_key.clone());
+        let wl = Rc4Writer::new(wl, None, Vec::new());
+
+        let rl = Rc4Reader::new(rl, None, Vec::new());
+        let mut wu = AeadWriter::new(wu, method, master_key);
+
+        // write addr first
+        wu.write(raw_addr.as_mut_slice()).await?;
+
+        // AeadReader/AeadWriter implement AsyncRead/AsyncWrite for the
+        // plain-data path, same as Rc4Reader/Rc4Writer, so this side is
+        // joined and relayed the same way, via copy_bidirectional.
+        let mut client = tokio::io::join(rl, wl);
+        let mut upstream = tokio::io::join(ru, wu);
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        return Ok(());
+    }
+
+    // builds the encryptor/decryptor from the configured method name rather
+    // than hardcoding rc4-md5, so new `Cipher` impls just need registering
+    // in `CipherKind`.
+    let kind = CipherKind::from_name(&cfg.method)
+        .ok_or_else(|| Error::new(ErrorKind::Other, "unsupported method"))?;
+    let master_key = kind.build().derive_master_key(cfg.password.as_bytes());
+
+    let ru = Rc4Reader::new(ru, Some(kind.build()), master_key.clone());
+    let wl = Rc4Writer::new(wl, None, Vec::new());
+
+    let rl = Rc4Reader::new(rl, None, Vec::new());
+    let mut wu = Rc4Writer::new(wu, Some(kind.build()), master_key);
+
+    // write addr first
+    wu.write_all(raw_addr.as_slice()).await?;
+
+    // now that both halves of each connection implement AsyncRead +
+    // AsyncWrite, join them back into a single stream per side and let
+    // tokio's copy_bidirectional drive the relay instead of a hand-rolled
+    // copy loop.
+    let mut client = tokio::io::join(rl, wl);
+    let mut upstream = tokio::io::join(ru, wu);
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+// reads the DST.ADDR/DST.PORT portion of a SOCKS5 request (the part after
+// VER/CMD/RSV/ATYP), returning both the raw `[atype][addr][port]` bytes
+// (used as the Shadowsocks target-address header) and the parsed SocketAddr.
+async fn read_dst_addr<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    atype: u8,
+) -> Result<(Vec<u8>, Result<SocketAddr, Error>), Error> {
     let mut raw_addr = vec![atype];
     let addr = match atype {
         ATYP_IPV4 => {
+            let mut buf = [0; 4];
             stream.read_exact(&mut buf).await?;
             let ipv4 = IpAddr::V4(Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]));
             raw_addr.append(&mut buf[..].to_owned());
@@ -116,64 +259,184 @@ async fn handle(mut stream: TcpStream, cfg: Cfg) -> Result<(), Error> {
         }
         _ => Err(Error::new(ErrorKind::Other, "not supported atype")),
     };
+    Ok((raw_addr, addr))
+}
 
-    stream
-        .write(&[SOCKS5_VER, 0, 0, 1, 0, 0, 0, 0, 0, 0])
-        .await?;
-
-    println!("start proxying");
-    println!(
-        "raw addr {:?}, proxy addr: {} by {}",
-        raw_addr, addr?, cfg.server_addr
-    );
-
-    // proxy addr
-    let upstream = TcpStream::connect(cfg.server_addr).await?;
-
-    let encryptor = Rc4::new(&cfg.password.as_bytes());
-    let decryptor = Rc4::new(&cfg.password.as_bytes());
+// parses a raw `[atype][addr][port]` header (as embedded in Shadowsocks UDP
+// packets) back into a SocketAddr plus the number of bytes consumed.
+fn parse_raw_addr(buf: &[u8]) -> Result<(SocketAddr, usize), Error> {
+    if buf.is_empty() {
+        return Err(Error::new(ErrorKind::Other, "empty addr header"));
+    }
+    match buf[0] {
+        ATYP_IPV4 => {
+            if buf.len() < 7 {
+                return Err(Error::new(ErrorKind::Other, "truncated ipv4 addr"));
+            }
+            let ip = IpAddr::V4(Ipv4Addr::new(buf[1], buf[2], buf[3], buf[4]));
+            let port = u16::from_be_bytes([buf[5], buf[6]]);
+            Ok((SocketAddr::new(ip, port), 7))
+        }
+        ATYPE_IPV6 => {
+            if buf.len() < 19 {
+                return Err(Error::new(ErrorKind::Other, "truncated ipv6 addr"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[1..17]);
+            let ip = IpAddr::V6(octets.into());
+            let port = u16::from_be_bytes([buf[17], buf[18]]);
+            Ok((SocketAddr::new(ip, port), 19))
+        }
+        ATYPE_HOST => {
+            if buf.len() < 2 {
+                return Err(Error::new(ErrorKind::Other, "truncated host addr"));
+            }
+            let host_len = buf[1] as usize;
+            if buf.len() < 2 + host_len + 2 {
+                return Err(Error::new(ErrorKind::Other, "truncated host addr"));
+            }
+            let host = String::from_utf8_lossy(&buf[2..2 + host_len]);
+            let port = u16::from_be_bytes([buf[2 + host_len], buf[3 + host_len]]);
+            let addr = format!("{}:{}", host, port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| Error::new(ErrorKind::Other, "unresolvable host"))?;
+            Ok((addr, 2 + host_len + 2))
+        }
+        _ => Err(Error::new(ErrorKind::Other, "not supported atype")),
+    }
+}
 
-    let (rl, wl) = stream.into_split();
-    let (ru, wu) = upstream.into_split();
+// builds the `[atype][addr][port]` header for a destination, in the same
+// format used by the Shadowsocks TCP target-address header.
+fn encode_raw_addr(addr: SocketAddr) -> Vec<u8> {
+    match addr {
+        SocketAddr::V4(a) => {
+            let mut out = vec![ATYP_IPV4];
+            out.extend_from_slice(&a.ip().octets());
+            out.extend_from_slice(&a.port().to_be_bytes());
+            out
+        }
+        SocketAddr::V6(a) => {
+            let mut out = vec![ATYPE_IPV6];
+            out.extend_from_slice(&a.ip().octets());
+            out.extend_from_slice(&a.port().to_be_bytes());
+            out
+        }
+    }
+}
 
-    let mut ru = Rc4Reader::new(ru, Some(decryptor));
-    let mut wl = Rc4Writer::new(wl, None);
+// handles a SOCKS5 UDP ASSOCIATE request: binds a local UDP socket, tells
+// the client where to send datagrams, and relays them to/from the upstream
+// ss-server, each datagram independently encrypted since UDP has no
+// ordering guarantees.
+async fn handle_udp_associate(mut ctrl: TcpStream, cfg: Cfg) -> Result<(), Error> {
+    let bind_ip = match ctrl.local_addr()? {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+    };
+    let local_udp = UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await?;
+    let bound_addr = local_udp.local_addr()?;
+    println!("udp associate bound at {}", bound_addr);
 
-    let mut rl = Rc4Reader::new(rl, None);
-    let mut wu = Rc4Writer::new(wu, Some(encryptor));
+    let mut reply = vec![SOCKS5_VER, 0, 0];
+    reply.extend_from_slice(&encode_raw_addr(bound_addr));
+    ctrl.write(&reply).await?;
 
-    // write addr first
-    wu.write(raw_addr.as_mut_slice()).await?;
+    let upstream_udp = UdpSocket::bind("0.0.0.0:0").await?;
+    upstream_udp.connect(&cfg.server_addr).await?;
 
-    // copy bidirectional
-    tokio::spawn(async move {
-        // read from local and write to upstream
-        copy1(&mut rl, &mut wu).await.unwrap();
-    });
+    // remembers only the most recently seen client address so upstream
+    // replies can be routed back to it; overwritten (not accumulated) on
+    // every datagram, since a SOCKS5 UDP association is 1:1 with the
+    // control TCP connection it's scoped to, and never fans a reply out to
+    // an address that didn't just send a request. The association is torn
+    // down once the client closes the control TCP connection.
+    let mut client_addr: Option<SocketAddr> = None;
+    let mut client_buf = [0u8; 65536];
+    let mut upstream_buf = [0u8; 65536];
+    let mut ctrl_buf = [0u8; 1];
 
-    // read from upstream and write to local
-    copy1(&mut ru, &mut wl).await.unwrap();
+    loop {
+        tokio::select! {
+            res = ctrl.read(&mut ctrl_buf) => {
+                if res? == 0 {
+                    println!("udp associate control connection closed");
+                    break;
+                }
+            }
+            res = local_udp.recv_from(&mut client_buf) => {
+                let (n, addr) = res?;
+                client_addr = Some(addr);
+                // +----+------+------+----------+----------+----------+
+                // |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+                // +----+------+------+----------+----------+----------+
+                // | 2  |  1   |  1   | Variable |    2     | Variable |
+                // +----+------+------+----------+----------+----------+
+                if n < 4 {
+                    continue;
+                }
+                let frag = client_buf[2];
+                if frag != 0 {
+                    println!("dropping fragmented udp packet");
+                    continue;
+                }
+                let (dst, consumed) = match parse_raw_addr(&client_buf[3..n]) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("bad udp header: {}", e);
+                        continue;
+                    }
+                };
+                let mut payload = encode_raw_addr(dst);
+                payload.extend_from_slice(&client_buf[3 + consumed..n]);
+                let sealed = seal_udp_payload(&cfg, &payload)?;
+                upstream_udp.send(&sealed).await?;
+            }
+            res = upstream_udp.recv(&mut upstream_buf) => {
+                let n = res?;
+                let payload = match open_udp_payload(&cfg, &upstream_buf[..n]) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("bad udp payload from upstream: {}", e);
+                        continue;
+                    }
+                };
+                let (src, consumed) = match parse_raw_addr(&payload) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("bad upstream addr header: {}", e);
+                        continue;
+                    }
+                };
+                let mut reply = vec![0, 0, 0];
+                reply.extend_from_slice(&encode_raw_addr(src));
+                reply.extend_from_slice(&payload[consumed..]);
+                if let Some(addr) = client_addr {
+                    local_udp.send_to(&reply, addr).await?;
+                }
+            }
+        }
+    }
     Ok(())
 }
 
-async fn copy1<'a, T: AsyncRead + Unpin, U: AsyncWrite + Unpin>(
-    reader: &'a mut Rc4Reader<T>,
-    writer: &'a mut Rc4Writer<U>,
-) -> Result<(), Error> {
-    let mut buf = [0; 1024];
-    loop {
-        let len = reader.read(&mut buf[..]).await?;
-
-        if len == 0 {
-            println!("break");
-            break;
-        } else {
-            println!("read {} bytes", len);
-        }
+fn seal_udp_payload(cfg: &Cfg, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    if let Some(method) = AeadMethod::from_name(&cfg.method) {
+        let master_key = method.derive_master_key(cfg.password.as_bytes());
+        aead::seal_packet(method, &master_key, payload)
+    } else {
+        Ok(stream::seal_packet(cfg.password.as_bytes(), payload))
+    }
+}
 
-        writer.write(&mut buf[..len]).await?
+fn open_udp_payload(cfg: &Cfg, packet: &[u8]) -> Result<Vec<u8>, Error> {
+    if let Some(method) = AeadMethod::from_name(&cfg.method) {
+        let master_key = method.derive_master_key(cfg.password.as_bytes());
+        aead::open_packet(method, &master_key, packet)
+    } else {
+        stream::open_packet(cfg.password.as_bytes(), packet)
     }
-    Ok(())
 }
 
 #[tokio::main]
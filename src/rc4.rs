@@ -1,6 +1,7 @@
 use md5::{Digest, Md5};
 const MD5_LENGTH: u32 = 16;
 const KEY_LEN: usize = 16;
+const IV_LEN: usize = 16;
 
 #[derive(Debug)]
 pub struct Rc4 {
@@ -31,7 +32,14 @@ impl Rc4 {
     // initialize RC4 instance with IV
     pub fn init(&mut self, iv: &[u8]) {
         let key = generate_rc4_key(&self.password, iv);
+        self.init_with_key(&key);
+    }
 
+    // runs the KSA (key-scheduling algorithm) over an already-derived
+    // session key, bypassing the password -> key expansion `init` performs.
+    // Used by [`Rc4::from_expanded_key`], whose caller has already expanded
+    // the password via [`generate_key`].
+    fn init_with_key(&mut self, key: &[u8]) {
         for i in 0..256 {
             self.state[i] = i as u8;
         }
@@ -46,6 +54,18 @@ impl Rc4 {
         self.init = true;
     }
 
+    // builds an already-initialized RC4 instance from an `expanded_key`
+    // (e.g. the output of [`generate_key`]) and an IV, the way
+    // [`crate::cipher::Cipher::new_session`] receives its key material.
+    // Equivalent to `Rc4::new(password).init(iv)` when `expanded_key` is
+    // `generate_key(password, KEY_LEN)`.
+    pub(crate) fn from_expanded_key(expanded_key: &[u8], iv: &[u8]) -> Rc4 {
+        let mut rc4 = Rc4::new(&[]);
+        let key = rc4_session_key(expanded_key, iv);
+        rc4.init_with_key(&key);
+        rc4
+    }
+
     // generates the next byte to be combined with a byte of the plain text / cipher.
     fn next_byte(&mut self) -> u8 {
         self.i = self.i.wrapping_add(1);
@@ -70,15 +90,23 @@ pub fn compute(data: &[u8]) -> Vec<u8> {
 }
 
 fn generate_rc4_key(password: &[u8], iv: &[u8]) -> Vec<u8> {
-    let mut hasher = Md5::new();
     let password = generate_key(password, KEY_LEN);
-    hasher.update(&password);
+    rc4_session_key(&password, iv)
+}
+
+// combines an already-expanded key with an IV the same way
+// `generate_rc4_key` combines a raw password with one, without repeating
+// the password -> key expansion step.
+fn rc4_session_key(expanded_key: &[u8], iv: &[u8]) -> Vec<u8> {
+    let mut hasher = Md5::new();
+    hasher.update(expanded_key);
     hasher.update(iv);
-    let key = hasher.finalize();
-    key.to_vec()
+    hasher.finalize().to_vec()
 }
 
-fn generate_key(data: &[u8], key_len: usize) -> Vec<u8> {
+// EVP_BytesToKey-style MD5 key expansion, also used by the AEAD ciphers to
+// derive their master key from the password.
+pub(crate) fn generate_key(data: &[u8], key_len: usize) -> Vec<u8> {
     let count = (key_len as f32 / MD5_LENGTH as f32).ceil() as u32;
     let mut key = Vec::from(&compute(data)[..]);
     let mut start = 0;
@@ -92,10 +120,51 @@ fn generate_key(data: &[u8], key_len: usize) -> Vec<u8> {
     key
 }
 
+/// [`crate::cipher::Cipher`] implementation for the legacy `rc4-md5`
+/// stream cipher.
+pub struct Rc4Cipher;
+
+impl crate::cipher::Cipher for Rc4Cipher {
+    fn key_size(&self) -> usize {
+        KEY_LEN
+    }
+
+    fn salt_or_iv_size(&self) -> usize {
+        IV_LEN
+    }
+
+    fn new_session(&self, key: &[u8], salt_or_iv: &[u8]) -> Box<dyn crate::cipher::CipherSession> {
+        Box::new(Rc4Session(Rc4::from_expanded_key(key, salt_or_iv)))
+    }
+}
+
+struct Rc4Session(Rc4);
+
+impl crate::cipher::CipherSession for Rc4Session {
+    fn encrypt_chunk(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut buf = plaintext.to_vec();
+        self.0.crypt_inplace(&mut buf);
+        Ok(buf)
+    }
+
+    fn decrypt_chunk(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        // RC4 is a symmetric stream cipher: decryption is the same
+        // XOR-with-keystream operation as encryption.
+        let mut buf = ciphertext.to_vec();
+        self.0.crypt_inplace(&mut buf);
+        Ok(buf)
+    }
+
+    fn overhead(&self) -> usize {
+        0
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::{compute, generate_key, Rc4};
+    use crate::cipher::Cipher;
 
     #[test]
     fn compute_test() {
@@ -129,4 +198,22 @@ mod tests {
             v.as_slice()
         );
     }
+
+    #[test]
+    fn rc4_cipher_matches_legacy_path() {
+        let password = "password";
+        let iv = "iv";
+
+        let mut legacy = Rc4::new(password.as_bytes());
+        legacy.init(iv.as_bytes());
+        let mut expected = [0u8; 10];
+        legacy.crypt_inplace(&mut expected);
+
+        let cipher = super::Rc4Cipher;
+        let key = generate_key(password.as_bytes(), cipher.key_size());
+        let mut session = cipher.new_session(&key, iv.as_bytes());
+        let actual = session.encrypt_chunk(&[0u8; 10]).unwrap();
+
+        assert_eq!(expected.as_slice(), actual.as_slice());
+    }
 }